@@ -0,0 +1,376 @@
+//! PIO-driven chip-select, for parts that need tight, guaranteed CS-to-clock
+//! timing that a GPIO toggled from software (e.g. via `ExclusiveDevice`)
+//! can't promise.
+//!
+//! A second side-set pin asserts CS around each [`SpiDevice::transaction`],
+//! with a configurable number of setup cycles before the first clock edge
+//! and hold cycles after the last, so [`SpiWithCs`] can be used as an
+//! `embedded-hal` [`SpiDevice`] directly, without composing it from a
+//! separately-driven CS pin.
+
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use fugit::HertzU32;
+use rp2040_hal::{
+    gpio::{AnyPin, FunctionNull, Pin, ValidFunction},
+    pio::{
+        Buffers, PIOBuilder, PIOExt, Running, StateMachine, StateMachineIndex, Tx, Rx,
+        UninitStateMachine, PIO,
+    },
+};
+
+use crate::Error;
+
+/// Index of the `out y, 32` instruction in [`build_program`]'s assembled
+/// output, whose delay field is patched with the configured setup cycles.
+const SETUP_INSTR_INDEX: usize = 1;
+
+/// Index of the `nop` instruction in [`build_program`]'s assembled output
+/// that runs with CS still asserted, whose delay field is patched with the
+/// configured hold cycles. CS is only deasserted by the `nop` after it.
+const HOLD_INSTR_INDEX: usize = 9;
+
+/// The number of bus-clock cycles CS is held asserted before the first clock
+/// edge and after the last, for [`SpiWithCs`].
+///
+/// Both fields must be between 1 and 8 (the PIO program's two-bit side-set
+/// only leaves 3 delay bits to encode them).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsTiming {
+    /// Cycles CS is held asserted before the first SCK edge.
+    pub setup: u8,
+    /// Cycles CS is held asserted after the last SCK edge.
+    pub hold: u8,
+}
+
+/// Overwrites the delay field of the instruction at `index` in `program`
+/// with `cycles - 1`, leaving its opcode and side-set value untouched. Used
+/// to patch the CS setup/hold cycle counts -- configured at runtime via
+/// [`CsTiming`] -- into the otherwise-static assembled program, the same way
+/// `apply_polarity` patches in CPOL after assembly.
+fn set_instruction_delay(
+    program: &mut pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }>,
+    index: usize,
+    cycles: u8,
+) {
+    let side_set = program.side_set;
+    let mut instruction = pio::Instruction::decode(program.code[index], side_set)
+        .expect("instruction at `index` failed to decode");
+    instruction.delay = cycles - 1;
+    program.code[index] = instruction.encode(side_set);
+}
+
+/// Assembles the CS-timing SPI PIO program: fixed at 8-bit words, with CS as
+/// side-set bit 1 (SCK stays bit 0) asserted low around every word of a
+/// transaction.
+///
+/// Each transaction is preceded by a single 32-bit control word holding
+/// `word_count - 1`, consumed by `out y, 32` right as CS is asserted; the
+/// state machine then clocks `word_count` bytes full-duplex, deasserting CS
+/// only once `Y` (decremented once per byte by `jmp y--`) reaches zero. The
+/// setup/hold delays are assembled as placeholders and patched in by
+/// [`build_cs_program`] afterwards, since `pio_asm!` only accepts literal
+/// delay counts.
+///
+/// Autopull/autopush are not used: `out y, 32` would race a simultaneous
+/// autopull refill of the OSR, so every FIFO access here is a manual
+/// `pull`/`push`, checked once per bit the same way `HalfDuplexSpi`'s
+/// program does.
+fn build_program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    pio_proc::pio_asm!(
+        ".side_set 2"
+
+        ".wrap_target"
+        "public entry_point:"
+        "  pull block               side 2"
+        "  out y, 32                side 0"
+        "wordloop:"
+        "  set x, 7                 side 0"
+        "bitloop:"
+        "  pull ifempty block       side 0"
+        "  out pins, 1              side 0 [1]"
+        "  in  pins, 1              side 1 [1]"
+        "  push iffull block        side 1"
+        "  jmp x-- bitloop          side 1"
+        "  jmp y-- wordloop         side 0"
+        "  nop                      side 0"
+        "  nop                      side 2"
+        ".wrap"
+    )
+    .program
+}
+
+/// Assembles [`build_program`]'s output and patches in `timing`'s setup/hold
+/// cycle counts.
+fn build_cs_program(timing: CsTiming) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut program = build_program();
+    set_instruction_delay(&mut program, SETUP_INSTR_INDEX, timing.setup);
+    set_instruction_delay(&mut program, HOLD_INSTR_INDEX, timing.hold);
+    program
+}
+
+/// Instance of an SPI Controller backed by a PIO state machine that also
+/// drives chip-select, implementing `embedded-hal`'s [`SpiDevice`] directly
+/// rather than needing to be composed with `ExclusiveDevice`.
+///
+/// Words are fixed at 8 bits. `cs` must be the GPIO immediately after `clk`
+/// (`cs.id().num == clk.id().num + 1`), since both are driven by the same
+/// two-bit side-set field.
+pub struct SpiWithCs<'pio, P, SMI, Miso, Mosi, Clk, Cs>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    Cs: AnyPin,
+{
+    pio: &'pio mut PIO<P>,
+    sm: StateMachine<(P, SMI), Running>,
+    tx: Tx<(P, SMI)>,
+    rx: Rx<(P, SMI)>,
+    miso: Pin<Miso::Id, P::PinFunction, Miso::Pull>,
+    mosi: Pin<Mosi::Id, P::PinFunction, Mosi::Pull>,
+    clk: Pin<Clk::Id, P::PinFunction, Clk::Pull>,
+    cs: Pin<Cs::Id, P::PinFunction, Cs::Pull>,
+}
+
+impl<'pio, P, SMI, Miso, Mosi, Clk, Cs> SpiWithCs<'pio, P, SMI, Miso, Mosi, Clk, Cs>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    Cs: AnyPin,
+{
+    /// Creates a new PIO-backed SPI Controller with PIO-driven chip-select.
+    ///
+    /// `pins` is `(miso, mosi, clk, cs)`; `cs` must be the GPIO immediately
+    /// after `clk`. The PIO block must have already been reset before using
+    /// this driver. Only mode 0 (CPOL=0, CPHA=0) is supported, since the
+    /// idle/asserted levels baked into the program assume SCK idles low.
+    pub fn new(
+        (pio, sm): (&'pio mut PIO<P>, UninitStateMachine<(P, SMI)>),
+        pins: (Miso, Mosi, Clk, Cs),
+        timing: CsTiming,
+        bus_freq: HertzU32,
+        clock_freq: HertzU32,
+    ) -> Result<Self, Error>
+    where
+        Miso: AnyPin<Function = FunctionNull>,
+        Miso::Id: ValidFunction<P::PinFunction>,
+        Mosi: AnyPin<Function = FunctionNull>,
+        Mosi::Id: ValidFunction<P::PinFunction>,
+        Clk: AnyPin<Function = FunctionNull>,
+        Clk::Id: ValidFunction<P::PinFunction>,
+        Cs: AnyPin<Function = FunctionNull>,
+        Cs::Id: ValidFunction<P::PinFunction>,
+    {
+        let (miso, mosi, clk, cs): (Miso::Type, Mosi::Type, Clk::Type, Cs::Type) =
+            (pins.0.into(), pins.1.into(), pins.2.into(), pins.3.into());
+
+        if cs.id().num != clk.id().num + 1 {
+            return Err(Error::CsPinNotAdjacentToClk);
+        }
+
+        if !(1..=8).contains(&timing.setup) || !(1..=8).contains(&timing.hold) {
+            return Err(Error::CsTimingOutOfRange);
+        }
+
+        let program = build_cs_program(timing);
+        let installed = pio.install(&program).unwrap();
+
+        // Per bit: `pull ifempty` + `out [1]` + `in [1]` + `push iffull` +
+        // one of `jmp x--`/`jmp y--` (always executed, whichever is taken).
+        let bit_freq = 7 * bus_freq;
+        let mut int = clock_freq / bit_freq;
+        let rem = clock_freq - (int * bit_freq);
+        let frac = (rem * 256) / bit_freq;
+
+        if !(1..=65536).contains(&int) || (int == 65536 && frac != 0) {
+            pio.uninstall(installed);
+            return Err(Error::ClockDivisorOutOfRange);
+        }
+
+        if int == 65536 {
+            int = 0;
+        }
+        let int: u16 = int as u16;
+        let frac: u8 = frac as u8;
+
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .buffers(Buffers::RxTx)
+            .out_pins(mosi.id().num, 1)
+            .in_pin_base(miso.id().num)
+            .side_set_pin_base(clk.id().num)
+            .out_shift_direction(rp2040_hal::pio::ShiftDirection::Left)
+            .autopull(false)
+            .pull_threshold(8)
+            .in_shift_direction(rp2040_hal::pio::ShiftDirection::Left)
+            .autopush(false)
+            .push_threshold(8)
+            .clock_divisor_fixed_point(int, frac)
+            .build(sm);
+
+        sm.set_pindirs([
+            (mosi.id().num, rp2040_hal::pio::PinDir::Output),
+            (clk.id().num, rp2040_hal::pio::PinDir::Output),
+            (cs.id().num, rp2040_hal::pio::PinDir::Output),
+            (miso.id().num, rp2040_hal::pio::PinDir::Input),
+        ]);
+
+        let miso: Pin<Miso::Id, P::PinFunction, Miso::Pull> = miso.into_function();
+        let mosi: Pin<Mosi::Id, P::PinFunction, Mosi::Pull> = mosi.into_function();
+        let clk: Pin<Clk::Id, P::PinFunction, Clk::Pull> = clk.into_function();
+        let cs: Pin<Cs::Id, P::PinFunction, Cs::Pull> = cs.into_function();
+
+        let sm = sm.start();
+
+        Ok(Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            miso,
+            mosi,
+            clk,
+            cs,
+        })
+    }
+
+    /// Frees the state machine and pins, returning an uninitialised state
+    /// machine that can be reused for another program.
+    #[allow(clippy::type_complexity)]
+    pub fn free(
+        self,
+    ) -> (
+        (Miso::Type, Mosi::Type, Clk::Type, Cs::Type),
+        UninitStateMachine<(P, SMI)>,
+    )
+    where
+        Miso::Id: ValidFunction<Miso::Function>,
+        Mosi::Id: ValidFunction<Mosi::Function>,
+        Clk::Id: ValidFunction<Clk::Function>,
+        Cs::Id: ValidFunction<Cs::Function>,
+    {
+        let Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            miso,
+            mosi,
+            clk,
+            cs,
+        } = self;
+        let (uninit, program) = sm.uninit(rx, tx);
+        pio.uninstall(program);
+
+        (
+            (
+                miso.reconfigure(),
+                mosi.reconfigure(),
+                clk.reconfigure(),
+                cs.reconfigure(),
+            ),
+            uninit,
+        )
+    }
+
+    /// Clocks one byte out while simultaneously clocking one byte in.
+    fn transfer_byte(&mut self, byte: u8) -> u8 {
+        while self.tx.is_full() {}
+        self.tx.write((byte as u32) << 24);
+
+        loop {
+            if let Some(word) = self.rx.read() {
+                return (word >> 24) as u8;
+            }
+        }
+    }
+}
+
+impl<P, SMI, Miso, Mosi, Clk, Cs> ErrorType for SpiWithCs<'_, P, SMI, Miso, Mosi, Clk, Cs>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    Cs: AnyPin,
+{
+    type Error = Error;
+}
+
+impl<P, SMI, Miso, Mosi, Clk, Cs> SpiDevice<u8> for SpiWithCs<'_, P, SMI, Miso, Mosi, Clk, Cs>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    Cs: AnyPin,
+{
+    /// Runs `operations` under one continuous PIO-driven CS assertion: CS is
+    /// asserted (after `timing.setup` cycles) before the first operation's
+    /// first bit and deasserted (after `timing.hold` cycles) only once the
+    /// last operation's last bit has been clocked, entirely by the state
+    /// machine counting down the word count pushed at the start -- Rust
+    /// doesn't need to signal which operation is last.
+    ///
+    /// [`Operation::DelayNs`] isn't supported, since honoring it would need
+    /// a hardware timer this crate doesn't otherwise depend on.
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        let mut word_count: usize = 0;
+        for op in operations.iter() {
+            word_count += match op {
+                Operation::Read(buf) => buf.len(),
+                Operation::Write(buf) => buf.len(),
+                Operation::Transfer(read, write) => read.len().max(write.len()),
+                Operation::TransferInPlace(buf) => buf.len(),
+                Operation::DelayNs(_) => return Err(Error::CsDelayUnsupported),
+            };
+        }
+
+        if word_count == 0 {
+            return Err(Error::CsTransactionEmpty);
+        }
+        debug_assert!(word_count <= u32::MAX as usize, "transaction too long");
+
+        while self.tx.is_full() {}
+        self.tx.write(word_count as u32 - 1);
+
+        for op in operations.iter_mut() {
+            match op {
+                Operation::Read(buf) => {
+                    for slot in buf.iter_mut() {
+                        *slot = self.transfer_byte(0);
+                    }
+                }
+                Operation::Write(buf) => {
+                    for &byte in buf.iter() {
+                        self.transfer_byte(byte);
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    let len = read.len().max(write.len());
+                    for i in 0..len {
+                        let out = write.get(i).copied().unwrap_or(0);
+                        let byte = self.transfer_byte(out);
+                        if let Some(slot) = read.get_mut(i) {
+                            *slot = byte;
+                        }
+                    }
+                }
+                Operation::TransferInPlace(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = self.transfer_byte(*byte);
+                    }
+                }
+                Operation::DelayNs(_) => unreachable!("rejected above"),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! DMA-paced transfers that saturate the PIO FIFOs without per-word CPU
+//! polling, by wiring one DMA channel to each of the state machine's TX and
+//! RX DREQs.
+
+use rp2040_hal::{
+    dma::{single_buffer, ReadTarget, SingleChannel, WriteTarget},
+    gpio::{AnyPin, Pin},
+    pio::{PIOExt, Running, StateMachine, StateMachineIndex, PIO},
+};
+
+use crate::Spi;
+
+/// Handle to an in-progress DMA-paced transfer started by
+/// [`Spi::transfer_dma`].
+///
+/// One DMA channel streams `tx_buf` into the state machine's TX FIFO while a
+/// second drains its RX FIFO into `rx_buf`, both paced by the state
+/// machine's DREQs, so the CPU is free to do other work until [`Self::wait`]
+/// is called.
+///
+/// The `rp2040-hal` version this crate is pinned to only supports
+/// word-at-a-time (`u32`) DMA transfers to/from PIO FIFOs, so `tx_buf` and
+/// `rx_buf` are `u32` slices rather than `u8` ones; each element occupies one
+/// FIFO word, shifted the same way [`Spi`]'s byte-oriented methods
+/// left-justify `NBITS` bits into the top of the word.
+pub struct Transfer<'pio, P, SMI, Miso, Mosi, Clk, TxCh, RxCh, TxBuf, RxBuf, const NBITS: u8>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    TxCh: SingleChannel,
+    RxCh: SingleChannel,
+    TxBuf: ReadTarget<ReceivedWord = u32>,
+    RxBuf: WriteTarget<TransmittedWord = u32>,
+{
+    pio: &'pio mut PIO<P>,
+    sm: StateMachine<(P, SMI), Running>,
+    miso: Pin<Miso::Id, P::PinFunction, Miso::Pull>,
+    mosi: Pin<Mosi::Id, P::PinFunction, Mosi::Pull>,
+    clk: Pin<Clk::Id, P::PinFunction, Clk::Pull>,
+    tx: single_buffer::Transfer<TxCh, TxBuf, rp2040_hal::pio::Tx<(P, SMI)>>,
+    rx: single_buffer::Transfer<RxCh, rp2040_hal::pio::Rx<(P, SMI)>, RxBuf>,
+}
+
+impl<'pio, P, SMI, Miso, Mosi, Clk, TxCh, RxCh, TxBuf, RxBuf, const NBITS: u8>
+    Transfer<'pio, P, SMI, Miso, Mosi, Clk, TxCh, RxCh, TxBuf, RxBuf, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+    TxCh: SingleChannel,
+    RxCh: SingleChannel,
+    TxBuf: ReadTarget<ReceivedWord = u32>,
+    RxBuf: WriteTarget<TransmittedWord = u32>,
+{
+    /// Claims `tx_channel`/`rx_channel` and starts streaming `tx_buf` into
+    /// the TX FIFO and the RX FIFO into `rx_buf`.
+    pub(crate) fn start(
+        spi: Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>,
+        tx_channel: TxCh,
+        rx_channel: RxCh,
+        tx_buf: TxBuf,
+        rx_buf: RxBuf,
+    ) -> Self {
+        let Spi {
+            pio,
+            sm,
+            tx,
+            rx,
+            miso,
+            mosi,
+            clk,
+        } = spi;
+
+        let tx = single_buffer::Config::new(tx_channel, tx_buf, tx).start();
+        let rx = single_buffer::Config::new(rx_channel, rx, rx_buf).start();
+
+        Self {
+            pio,
+            sm,
+            miso,
+            mosi,
+            clk,
+            tx,
+            rx,
+        }
+    }
+
+    /// Returns `true` once both the TX and RX DMA channels have completed.
+    pub fn is_done(&self) -> bool {
+        self.tx.is_done() && self.rx.is_done()
+    }
+
+    /// Blocks until both DMA channels have completed, then hands back the
+    /// buffers and channels along with a [`Spi`] ready for further
+    /// transfers.
+    #[allow(clippy::type_complexity)]
+    pub fn wait(
+        self,
+    ) -> (
+        Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>,
+        TxCh,
+        RxCh,
+        TxBuf,
+        RxBuf,
+    ) {
+        let (tx_channel, tx_buf, tx) = self.tx.wait();
+        let (rx_channel, rx, rx_buf) = self.rx.wait();
+
+        let spi = Spi {
+            pio: self.pio,
+            sm: self.sm,
+            tx,
+            rx,
+            miso: self.miso,
+            mosi: self.mosi,
+            clk: self.clk,
+        };
+
+        (spi, tx_channel, rx_channel, tx_buf, rx_buf)
+    }
+}
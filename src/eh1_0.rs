@@ -0,0 +1,70 @@
+use embedded_hal::spi::{ErrorType, SpiBus};
+use rp2040_hal::gpio::AnyPin;
+use rp2040_hal::pio::{PIOExt, StateMachineIndex};
+
+use crate::{Error, Spi};
+
+impl embedded_hal::spi::Error for Error {
+    fn kind(&self) -> embedded_hal::spi::ErrorKind {
+        embedded_hal::spi::ErrorKind::Other
+    }
+}
+
+impl<P, SMI, Miso, Mosi, Clk, const NBITS: u8> ErrorType for Spi<'_, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    type Error = Error;
+}
+
+impl<P, SMI, Miso, Mosi, Clk, const NBITS: u8> SpiBus<u8>
+    for Spi<'_, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_word(0) as u8;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        for &word in words {
+            self.transfer_word(word as u32);
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let len = read.len().max(write.len());
+        for i in 0..len {
+            let out = write.get(i).copied().unwrap_or(0);
+            let word = self.transfer_word(out as u32) as u8;
+            if let Some(slot) = read.get_mut(i) {
+                *slot = word;
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words.iter_mut() {
+            *word = self.transfer_word(*word as u32) as u8;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        while !self.tx.is_empty() {}
+        Ok(())
+    }
+}
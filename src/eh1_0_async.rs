@@ -0,0 +1,281 @@
+//! Async `embedded-hal-async` [`SpiBus`] implementation, woken by the PIO's
+//! RX-not-empty/TX-not-full interrupts instead of busy-waiting.
+//!
+//! Each `.await` point registers a [`core::task::Waker`] and enables the
+//! corresponding FIFO interrupt on the state machine's chosen [`PioIRQ`]
+//! line, so the executor is free to run other tasks until the PIO raises
+//! it. That line is shared by all four state machines on a PIO block, so the
+//! application must forward its own interrupt handler to [`on_pio0_irq`] or
+//! [`on_pio1_irq`], e.g.:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn PIO0_IRQ_0() {
+//!     spi_pio::on_pio0_irq(spi_pio::PioIRQ::Irq0);
+//! }
+//! ```
+
+use core::cell::Cell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+use critical_section::Mutex;
+use embedded_hal_async::spi::SpiBus;
+use rp2040_hal::gpio::AnyPin;
+use rp2040_hal::pac;
+use rp2040_hal::pio::{PIOExt, PioIRQ, StateMachineIndex};
+
+use crate::{prime_bit_count, Error, Spi};
+
+/// Number of (PIO block, state machine) combinations: 2 PIO blocks of 4
+/// state machines each.
+const NUM_WAKERS: usize = 8;
+
+/// A `Waker` guarded by a critical section, woken from interrupt context.
+struct IrqWaker(Mutex<Cell<Option<Waker>>>);
+
+impl IrqWaker {
+    const fn new() -> Self {
+        Self(Mutex::new(Cell::new(None)))
+    }
+
+    fn register(&self, waker: &Waker) {
+        critical_section::with(|cs| self.0.borrow(cs).replace(Some(waker.clone())));
+    }
+
+    fn wake(&self) {
+        critical_section::with(|cs| {
+            if let Some(waker) = self.0.borrow(cs).take() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+static WAKERS: [IrqWaker; NUM_WAKERS] = [
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+    IrqWaker::new(),
+];
+
+fn waker_index(pio_id: usize, sm_id: usize) -> usize {
+    pio_id * 4 + sm_id
+}
+
+fn irq_index(irq: PioIRQ) -> usize {
+    match irq {
+        PioIRQ::Irq0 => 0,
+        PioIRQ::Irq1 => 1,
+    }
+}
+
+/// Clears `bits` in `register` using the RP2040's atomic `CLR` alias rather
+/// than a plain read-modify-write, so this can't race a concurrent atomic
+/// `SET` of a different bit done by [`rp2040_hal::pio::Rx::enable_rx_not_empty_interrupt`]/
+/// [`rp2040_hal::pio::Tx::enable_tx_not_full_interrupt`].
+///
+/// # Safety
+/// `register` must point to a register providing atomic aliases (see
+/// section 2.1.2 of the RP2040 datasheet).
+#[inline]
+unsafe fn clear_bits(register: *mut u32, bits: u32) {
+    let alias = (register as usize + 0x3000) as *mut u32;
+    core::ptr::write_volatile(alias, bits);
+}
+
+/// Wakes every state machine on `pio_id` whose RX-not-empty or TX-not-full
+/// flag is currently asserted on `irq`'s line, masking those two sources off
+/// so the (shared) line doesn't keep re-firing before the woken future gets
+/// a chance to act. The future re-checks the real FIFO state itself and
+/// re-enables whichever interrupt it's still waiting on, so it doesn't
+/// matter if the flag this sees belongs to a transfer that's already moved
+/// on by the time the handler runs.
+fn handle_irq(pio_id: usize, irq: PioIRQ) {
+    let block = if pio_id == 0 {
+        pac::PIO0::ptr()
+    } else {
+        pac::PIO1::ptr()
+    };
+
+    // SAFETY: reads the masked interrupt status, then clears only the bits
+    // this module's futures own, via the atomic CLR alias.
+    let sm_irq = unsafe { (*block).sm_irq(irq_index(irq)) };
+    let ints = sm_irq.irq_ints().read().bits();
+
+    for sm_id in 0..4 {
+        let sm_mask = 0b0001_0001u32 << sm_id;
+        if ints & sm_mask != 0 {
+            unsafe { clear_bits(sm_irq.irq_inte().as_ptr(), sm_mask) };
+            WAKERS[waker_index(pio_id, sm_id)].wake();
+        }
+    }
+}
+
+/// Forward PIO0's `PIO0_IRQ_0`/`PIO0_IRQ_1` interrupt here (passing the
+/// matching [`PioIRQ`]) to wake any [`AsyncSpi`] transfer waiting on it.
+pub fn on_pio0_irq(irq: PioIRQ) {
+    handle_irq(0, irq);
+}
+
+/// Forward PIO1's `PIO1_IRQ_0`/`PIO1_IRQ_1` interrupt here (passing the
+/// matching [`PioIRQ`]) to wake any [`AsyncSpi`] transfer waiting on it.
+pub fn on_pio1_irq(irq: PioIRQ) {
+    handle_irq(1, irq);
+}
+
+/// An async `embedded-hal-async` `SpiBus` wrapping a blocking [`Spi`].
+///
+/// Created via [`Spi::into_async`]. Unlike [`Spi`]'s `embedded-hal` `SpiBus`
+/// impl, every FIFO wait yields to the executor instead of busy-polling, at
+/// the cost of the application needing to forward the PIO's interrupt to
+/// [`on_pio0_irq`]/[`on_pio1_irq`] (see the crate-level docs for an
+/// example).
+pub struct AsyncSpi<'pio, P, SMI, Miso, Mosi, Clk, const NBITS: u8 = 8>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    spi: Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>,
+    irq: PioIRQ,
+}
+
+impl<'pio, P, SMI, Miso, Mosi, Clk, const NBITS: u8> AsyncSpi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    pub(crate) fn new(spi: Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>, irq: PioIRQ) -> Self {
+        Self { spi, irq }
+    }
+
+    /// Returns the wrapped blocking [`Spi`].
+    pub fn into_inner(self) -> Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS> {
+        self.spi
+    }
+
+    fn waker_index(&self) -> usize {
+        waker_index(P::id(), SMI::id())
+    }
+
+    async fn wait_tx_not_full(&mut self) {
+        let irq = self.irq;
+        let idx = self.waker_index();
+        let tx = &mut self.spi.tx;
+        poll_fn(|cx| {
+            if !tx.is_full() {
+                Poll::Ready(())
+            } else {
+                WAKERS[idx].register(cx.waker());
+                tx.enable_tx_not_full_interrupt(irq);
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn wait_rx_not_empty(&mut self) {
+        let irq = self.irq;
+        let idx = self.waker_index();
+        let rx = &mut self.spi.rx;
+        poll_fn(|cx| {
+            if !rx.is_empty() {
+                Poll::Ready(())
+            } else {
+                WAKERS[idx].register(cx.waker());
+                rx.enable_rx_not_empty_interrupt(irq);
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Clocks out the top `NBITS` bits of `word` while clocking `NBITS` bits
+    /// in, yielding to the executor for both FIFO waits. Always re-primes
+    /// `X`/`Y` for `NBITS` first, so interleaving this with
+    /// [`Spi::write_bits`]/[`Spi::read_bits`] on the same (freed and
+    /// re-wrapped) `Spi` is safe.
+    async fn transfer_word(&mut self, word: u32) -> u32 {
+        prime_bit_count(&mut self.spi.sm, NBITS);
+
+        self.wait_tx_not_full().await;
+        self.spi.tx.write(word << (32 - u32::from(NBITS)));
+
+        self.wait_rx_not_empty().await;
+        // The wait above only returns once `read()` will succeed.
+        self.spi.rx.read().unwrap_or(0) >> (32 - u32::from(NBITS))
+    }
+}
+
+impl<P, SMI, Miso, Mosi, Clk, const NBITS: u8> embedded_hal_async::spi::ErrorType
+    for AsyncSpi<'_, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    type Error = Error;
+}
+
+impl<P, SMI, Miso, Mosi, Clk, const NBITS: u8> SpiBus<u8>
+    for AsyncSpi<'_, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_word(0).await as u8;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        for &word in words {
+            self.transfer_word(word as u32).await;
+        }
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let len = read.len().max(write.len());
+        for i in 0..len {
+            let out = write.get(i).copied().unwrap_or(0);
+            let word = self.transfer_word(out as u32).await as u8;
+            if let Some(slot) = read.get_mut(i) {
+                *slot = word;
+            }
+        }
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words.iter_mut() {
+            *word = self.transfer_word(*word as u32).await as u8;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // There's no "TX FIFO empty" interrupt source, only "not full", so
+        // fall back to polling here exactly like the blocking `SpiBus` impl
+        // does.
+        while !self.spi.tx.is_empty() {}
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+//! Half-duplex (3-wire) SPI, where a single bidirectional pin carries both
+//! the write and read phases of a transfer instead of separate MOSI/MISO
+//! lines. Useful for parts like the CYW43 wireless chip and single-data-line
+//! displays/sensors.
+
+use fugit::HertzU32;
+use rp2040_hal::{
+    gpio::{AnyPin, FunctionNull, Pin, ValidFunction},
+    pio::{
+        Buffers, PIOBuilder, PIOExt, PinDir, Running, ShiftDirection, StateMachine,
+        StateMachineIndex, Tx, Rx, UninitStateMachine, PIO,
+    },
+};
+
+use crate::Error;
+
+/// Half-duplex (3-wire) SPI control word layout and bit budget.
+const MAX_PHASE_BITS: u32 = 0xFFFF;
+
+/// Assembles the half-duplex SPI PIO program.
+///
+/// Each transfer is preceded by a single 32-bit control word: the upper 16
+/// bits hold `write_bits - 1` and the lower 16 bits hold `read_bits - 1`.
+/// The state machine drives the data pin as an output for the write phase,
+/// then issues `set pindirs, 0` to turn it into an input for the read phase.
+/// Because that direction switch only ever happens right after the falling
+/// edge that clocks out the last write bit (while SCK is held low by the
+/// `side 0` that follows it), the turnaround never introduces a spurious
+/// clock edge on the shared data line.
+fn build_program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    pio_proc::pio_asm!(
+        ".side_set 1"
+
+        ".wrap_target"
+        "public entry_point:"
+        "  pull block               side 0"
+        "  out x, 16                side 0"
+        "  out y, 16                side 0"
+        "  set pindirs, 1           side 0"
+        "write_bitloop:"
+        "  pull ifempty block       side 0"
+        "  out pins, 1              side 0 [1]"
+        "  nop                      side 1 [1]"
+        "  jmp x-- write_bitloop    side 0"
+        "  set pindirs, 0           side 0"
+        "read_bitloop:"
+        "  nop                     side 1 [1]"
+        "  in pins, 1              side 0 [1]"
+        "  push iffull block       side 0"
+        "  jmp y-- read_bitloop    side 0"
+        ".wrap"
+    )
+    .program
+}
+
+/// Instance of a half-duplex (3-wire) SPI Controller backed by a PIO state
+/// machine, using a single bidirectional pin for both MOSI and MISO.
+///
+/// `NBITS` is the word size in bits used to frame the write/read buffers
+/// passed to [`Self::transfer_half_duplex`] and defaults to `8`.
+pub struct HalfDuplexSpi<'pio, P, SMI, Clk, Data, const NBITS: u8 = 8>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Clk: AnyPin,
+    Data: AnyPin,
+{
+    pio: &'pio mut PIO<P>,
+    sm: StateMachine<(P, SMI), Running>,
+    tx: Tx<(P, SMI)>,
+    rx: Rx<(P, SMI)>,
+    clk: Pin<Clk::Id, P::PinFunction, Clk::Pull>,
+    data: Pin<Data::Id, P::PinFunction, Data::Pull>,
+}
+
+impl<'pio, P, SMI, Clk, Data, const NBITS: u8> HalfDuplexSpi<'pio, P, SMI, Clk, Data, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Clk: AnyPin,
+    Data: AnyPin,
+{
+    /// Creates a new PIO-backed half-duplex SPI Controller.
+    ///
+    /// `pins` is `(clk, data)`. The PIO block must have already been reset
+    /// before using this driver.
+    pub fn new(
+        (pio, sm): (&'pio mut PIO<P>, UninitStateMachine<(P, SMI)>),
+        pins: (Clk, Data),
+        bus_freq: HertzU32,
+        clock_freq: HertzU32,
+    ) -> Result<Self, Error>
+    where
+        Clk: AnyPin<Function = FunctionNull>,
+        Clk::Id: ValidFunction<P::PinFunction>,
+        Data: AnyPin<Function = FunctionNull>,
+        Data::Id: ValidFunction<P::PinFunction>,
+    {
+        let (clk, data): (Clk::Type, Data::Type) = (pins.0.into(), pins.1.into());
+
+        let program = build_program();
+        let installed = pio.install(&program).unwrap();
+
+        // 5 PIO cycles per data bit: `out [1]` + `nop [1]` + `jmp` (same
+        // shape for both the write and read bitloops).
+        let bit_freq = 5 * bus_freq;
+        let mut int = clock_freq / bit_freq;
+        let rem = clock_freq - (int * bit_freq);
+        let frac = (rem * 256) / bit_freq;
+
+        if !(1..=65536).contains(&int) || (int == 65536 && frac != 0) {
+            pio.uninstall(installed);
+            return Err(Error::ClockDivisorOutOfRange);
+        }
+
+        if int == 65536 {
+            int = 0;
+        }
+        let int: u16 = int as u16;
+        let frac: u8 = frac as u8;
+
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .buffers(Buffers::RxTx)
+            .set_pins(data.id().num, 1)
+            .out_pins(data.id().num, 1)
+            .in_pin_base(data.id().num)
+            .side_set_pin_base(clk.id().num)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(false)
+            .pull_threshold(NBITS)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(false)
+            .push_threshold(NBITS)
+            .clock_divisor_fixed_point(int, frac)
+            .build(sm);
+
+        sm.set_pindirs([
+            (clk.id().num, PinDir::Output),
+            (data.id().num, PinDir::Output),
+        ]);
+
+        let clk: Pin<Clk::Id, P::PinFunction, Clk::Pull> = clk.into_function();
+        let data: Pin<Data::Id, P::PinFunction, Data::Pull> = data.into_function();
+
+        let sm = sm.start();
+
+        Ok(Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            clk,
+            data,
+        })
+    }
+
+    /// Frees the state machine and pins, returning an uninitialised state
+    /// machine that can be reused for another program.
+    #[allow(clippy::type_complexity)]
+    pub fn free(
+        self,
+    ) -> ((Clk::Type, Data::Type), UninitStateMachine<(P, SMI)>)
+    where
+        Clk::Id: ValidFunction<Clk::Function>,
+        Data::Id: ValidFunction<Data::Function>,
+    {
+        let Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            clk,
+            data,
+        } = self;
+        let (uninit, program) = sm.uninit(rx, tx);
+        pio.uninstall(program);
+
+        ((clk.reconfigure(), data.reconfigure()), uninit)
+    }
+
+    /// Performs a half-duplex transfer: `write` is clocked out first with
+    /// the data pin driven as an output, then the pin direction is flipped
+    /// and `read.len()` bytes are clocked in.
+    ///
+    /// Both `write` and `read` must be non-empty, since the PIO program
+    /// encodes each phase's length as `bits - 1`.
+    pub fn transfer_half_duplex(&mut self, write: &[u8], read: &mut [u8]) -> Result<(), Error> {
+        if write.is_empty() || read.is_empty() {
+            return Err(Error::HalfDuplexPhaseEmpty);
+        }
+
+        let write_bits = write.len() as u32 * u32::from(NBITS) - 1;
+        let read_bits = read.len() as u32 * u32::from(NBITS) - 1;
+        if write_bits > MAX_PHASE_BITS || read_bits > MAX_PHASE_BITS {
+            return Err(Error::TransferTooLong);
+        }
+
+        while self.tx.is_full() {}
+        self.tx.write((write_bits << 16) | read_bits);
+
+        let mut write_iter = write.iter();
+        let mut bits_to_write = write.len();
+        let mut bits_to_read = read.len();
+        let mut read_iter = read.iter_mut();
+
+        while bits_to_write > 0 || bits_to_read > 0 {
+            if bits_to_write > 0 && !self.tx.is_full() {
+                self.tx.write_u8_replicated(*write_iter.next().unwrap());
+                bits_to_write -= 1;
+            }
+
+            if bits_to_read > 0 {
+                if let Some(word) = self.rx.read() {
+                    *read_iter.next().unwrap() = (word >> (32 - NBITS)) as u8;
+                    bits_to_read -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
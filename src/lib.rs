@@ -0,0 +1,490 @@
+#![no_std]
+//! Implements a full-duplex SPI Controller using the RP2040 PIO block.
+//!
+//! This lets you run an SPI bus on (almost) any three pins, freeing up the
+//! two hardware SPI peripherals for other uses. A single PIO state machine
+//! drives SCK via side-set while shifting data out on MOSI and in on MISO in
+//! lock-step, so a single [`Spi`] only ever needs one of the four PIO state
+//! machines.
+//!
+//! # Pin mapping
+//! - Side-set pin 0 is SCK
+//! - OUT pin 0 is MOSI
+//! - IN pin 0 is MISO
+//!
+//! Autopush and autopull are both disabled: the hardware's shift-count
+//! threshold is fixed at configuration time, which can't track a per-call
+//! `nbits` that changes at runtime, so the assembled program instead does
+//! one unconditional, blocking `pull`/`push` per word itself, positioned by
+//! the trip count rather than by a threshold register. Before every transfer
+//! the scratch registers `X`/`Y` are (re)primed with the bit count to shift,
+//! via [`rp2040_hal::pio::StateMachine::exec_instruction`] rather than by
+//! baking it into the assembled program, so [`Spi::write_bits`] and
+//! [`Spi::read_bits`] can drive any width from the same running state
+//! machine as the byte-oriented `embedded-hal` traits. `NBITS`, and any
+//! `nbits` passed at runtime, must be between 2 and 32.
+#![allow(clippy::type_complexity)]
+
+mod cs;
+mod dma;
+mod eh1_0;
+mod eh1_0_async;
+mod half_duplex;
+
+pub use cs::{CsTiming, SpiWithCs};
+pub use dma::Transfer;
+pub use eh1_0_async::{on_pio0_irq, on_pio1_irq, AsyncSpi};
+pub use half_duplex::HalfDuplexSpi;
+pub use rp2040_hal::pio::PioIRQ;
+
+use embedded_hal::spi::{Mode, Phase, Polarity};
+use fugit::HertzU32;
+use rp2040_hal::{
+    dma::{ReadTarget, SingleChannel, WriteTarget},
+    gpio::{AnyPin, FunctionNull, Pin, ValidFunction},
+    pio::{
+        Buffers, PIOBuilder, PIOExt, Running, ShiftDirection, StateMachine, StateMachineIndex,
+        Tx, Rx, UninitStateMachine, ValidStateMachine, PIO,
+    },
+};
+
+/// Errors which can occur when configuring or using [`Spi`].
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The requested bus frequency could not be reached with the available
+    /// PIO clock dividers, i.e. the ratio between `clock_freq` and
+    /// `bus_freq` was not within `[1.0, 65536.0]`.
+    ClockDivisorOutOfRange,
+    /// [`HalfDuplexSpi::transfer_half_duplex`] was called with an empty
+    /// `write` or `read` buffer, which the half-duplex PIO program cannot
+    /// represent (it encodes each phase's length as `bits - 1`).
+    HalfDuplexPhaseEmpty,
+    /// A [`HalfDuplexSpi::transfer_half_duplex`] phase was longer than the
+    /// 16 bits of the control word can encode (8192 bytes at 8 bits/word).
+    TransferTooLong,
+    /// [`SpiWithCs::new`] was given a `cs` pin that isn't the GPIO
+    /// immediately after `clk`, which the shared two-bit side-set field
+    /// requires.
+    CsPinNotAdjacentToClk,
+    /// [`CsTiming`]'s `setup` or `hold` was outside the `1..=8` cycles the
+    /// PIO program's delay field can represent.
+    CsTimingOutOfRange,
+    /// [`SpiWithCs`]'s [`SpiDevice::transaction`](embedded_hal::spi::SpiDevice::transaction)
+    /// was called with an empty operation list, which the PIO program cannot
+    /// represent (it encodes the word count as `word_count - 1`).
+    CsTransactionEmpty,
+    /// [`SpiWithCs`]'s [`SpiDevice::transaction`](embedded_hal::spi::SpiDevice::transaction)
+    /// was given an [`embedded_hal::spi::Operation::DelayNs`], which isn't
+    /// supported since honoring it would need a hardware timer this crate
+    /// doesn't otherwise depend on.
+    CsDelayUnsupported,
+}
+
+/// Assembles the SPI PIO program for the requested [`Phase`], returning the
+/// installable [`pio::Program`].
+///
+/// Both phases share the same pin mapping (side-set 0 is SCK, OUT/IN pin 0
+/// are MOSI/MISO), they only differ in when data is sampled relative to the
+/// clock edge.
+///
+/// Neither variant bakes a bit count into the assembled instructions: `X`
+/// drives the `jmp x--` trip count and is always reloaded from `Y` right
+/// before the next cycle's first bit, so the word size is purely a function
+/// of whatever [`prime_bit_count`] last wrote into `Y`. For the same reason,
+/// neither relies on autopull/autopush: the hardware's refill threshold is
+/// fixed at build time and can't track a runtime-variable `nbits`, so each
+/// variant instead does one unconditional `pull block`/`push block` of its
+/// own per word, placed at the point the trip count marks as a word
+/// boundary rather than gated by a threshold register. Both variants `pull`
+/// at the wrap target, before the word's first `out`, so the OSR always
+/// holds the word to be shifted before any bit of it is clocked out.
+fn build_program(phase: Phase) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    match phase {
+        Phase::CaptureOnFirstTransition => {
+            pio_proc::pio_asm!(
+                ".side_set 1"
+
+                ".wrap_target"
+                "  pull block         side 0"
+                "bitloop:"
+                "  out pins, 1        side 0 [1]"
+                "  in  pins, 1        side 1 [1]"
+                "  jmp x-- bitloop    side 1"
+                "  out pins, 1        side 0"
+                "  mov x, y           side 0"
+                "  in  pins, 1        side 1"
+                "  push block         side 1"
+                ".wrap"
+            )
+            .program
+        }
+        Phase::CaptureOnSecondTransition => {
+            pio_proc::pio_asm!(
+                ".side_set 1"
+
+                ".wrap_target"
+                "  pull block         side 0"
+                "  out pins, 1        side 0"
+                "  mov x, y           side 1"
+                "bitloop:"
+                "  in  pins, 1        side 1 [1]"
+                "  out pins, 1        side 0 [1]"
+                "  jmp x-- bitloop    side 0"
+                "  in  pins, 1        side 1"
+                "  push block         side 1"
+                ".wrap"
+            )
+            .program
+        }
+    }
+}
+
+/// Reloads the scratch registers that drive the SPI bitloop's trip count
+/// with `nbits` worth of clock edges, via a directly executed `SET`
+/// instruction rather than a FIFO word, so it can be called on a state
+/// machine that's already running.
+///
+/// Both `X` (the count the `jmp x--` instruction tests) and `Y` (the
+/// constant the program copies back into `X` at the end of every cycle) are
+/// set, so the new width takes effect starting with the very next cycle and
+/// every cycle after that, without needing to prime `X` again.
+///
+/// `nbits` must be between 2 and 32: one bit is shifted by the bitloop body
+/// for every unit of the trip count plus one more by the single out/in pair
+/// that follows it, so the trip count written to the registers is
+/// `nbits - 2`.
+fn prime_bit_count<SM: ValidStateMachine, State>(sm: &mut StateMachine<SM, State>, nbits: u8) {
+    debug_assert!((2..=32).contains(&nbits), "nbits must be between 2 and 32");
+    let trip_count = nbits - 2;
+
+    for destination in [pio::SetDestination::Y, pio::SetDestination::X] {
+        sm.exec_instruction(pio::Instruction {
+            operands: pio::InstructionOperands::SET {
+                destination,
+                data: trip_count,
+            },
+            delay: 0,
+            side_set: None,
+        });
+    }
+}
+
+/// Flips the idle level of the side-set (SCK) pin in an assembled program,
+/// turning a CPOL=0 program into a CPOL=1 one. The side-set bit is always
+/// the MSB of each instruction's delay/side-set field, so toggling it on
+/// every instruction is equivalent to inverting SCK.
+fn apply_polarity(
+    mut program: pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }>,
+    polarity: Polarity,
+) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    const SIDE_SET_BIT: u16 = 1 << 12;
+
+    if polarity == Polarity::IdleHigh {
+        for instr in program.code.iter_mut() {
+            *instr ^= SIDE_SET_BIT;
+        }
+    }
+
+    program
+}
+
+/// Instance of an SPI Controller backed by a PIO state machine.
+///
+/// `NBITS` is the word size in bits and defaults to `8`, matching the
+/// `embedded-hal` byte-oriented traits implemented for this type.
+pub struct Spi<'pio, P, SMI, Miso, Mosi, Clk, const NBITS: u8 = 8>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    pio: &'pio mut PIO<P>,
+    sm: StateMachine<(P, SMI), Running>,
+    tx: Tx<(P, SMI)>,
+    rx: Rx<(P, SMI)>,
+    miso: Pin<Miso::Id, P::PinFunction, Miso::Pull>,
+    mosi: Pin<Mosi::Id, P::PinFunction, Mosi::Pull>,
+    clk: Pin<Clk::Id, P::PinFunction, Clk::Pull>,
+}
+
+impl<'pio, P, SMI, Miso, Mosi, Clk, const NBITS: u8> Spi<'pio, P, SMI, Miso, Mosi, Clk, NBITS>
+where
+    P: PIOExt,
+    SMI: StateMachineIndex,
+    Miso: AnyPin,
+    Mosi: AnyPin,
+    Clk: AnyPin,
+{
+    /// Creates a new PIO-backed SPI Controller.
+    ///
+    /// `pins` is `(miso, mosi, clk)`. The PIO block must have already been
+    /// reset (e.g. via [`rp2040_hal::pac::RESETS`]) before using this
+    /// driver.
+    pub fn new(
+        (pio, sm): (&'pio mut PIO<P>, UninitStateMachine<(P, SMI)>),
+        pins: (Miso, Mosi, Clk),
+        mode: Mode,
+        bus_freq: HertzU32,
+        clock_freq: HertzU32,
+    ) -> Result<Self, Error>
+    where
+        Miso: AnyPin<Function = FunctionNull>,
+        Miso::Id: ValidFunction<P::PinFunction>,
+        Mosi: AnyPin<Function = FunctionNull>,
+        Mosi::Id: ValidFunction<P::PinFunction>,
+        Clk: AnyPin<Function = FunctionNull>,
+        Clk::Id: ValidFunction<P::PinFunction>,
+    {
+        let (miso, mosi, clk): (Miso::Type, Mosi::Type, Clk::Type) =
+            (pins.0.into(), pins.1.into(), pins.2.into());
+
+        let program = apply_polarity(build_program(mode.phase), mode.polarity);
+
+        let installed = pio.install(&program).unwrap();
+
+        // One SCK edge per bit, with a [1] delay on either side of the
+        // program to balance the two halves of the clock cycle, plus a
+        // once-per-word `pull block`/`push block` pair. The looped part of
+        // the bitloop costs 5 cycles/pass over `nbits - 1` passes, and the
+        // remaining once-per-word instructions (the single out/in pair plus
+        // pull/push/housekeeping) always add up to exactly 5 more cycles,
+        // for a total of `5 * nbits` cycles/word regardless of `nbits` --
+        // i.e. exactly 5 cycles/bit, not an approximation.
+        let bit_freq = 5 * bus_freq;
+        let mut int = clock_freq / bit_freq;
+        let rem = clock_freq - (int * bit_freq);
+        let frac = (rem * 256) / bit_freq;
+
+        if !(1..=65536).contains(&int) || (int == 65536 && frac != 0) {
+            pio.uninstall(installed);
+            return Err(Error::ClockDivisorOutOfRange);
+        }
+
+        // 65536.0 is represented as 0 in the PIO's clock divider.
+        if int == 65536 {
+            int = 0;
+        }
+        let int: u16 = int as u16;
+        let frac: u8 = frac as u8;
+
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .buffers(Buffers::RxTx)
+            .out_pins(mosi.id().num, 1)
+            .in_pin_base(miso.id().num)
+            .side_set_pin_base(clk.id().num)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(false)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(false)
+            .clock_divisor_fixed_point(int, frac)
+            .build(sm);
+
+        sm.set_pindirs([
+            (mosi.id().num, rp2040_hal::pio::PinDir::Output),
+            (clk.id().num, rp2040_hal::pio::PinDir::Output),
+            (miso.id().num, rp2040_hal::pio::PinDir::Input),
+        ]);
+
+        // Prime the bitloop for NBITS-wide words before the first cycle
+        // ever runs; from then on the program keeps reloading X from Y
+        // itself every cycle, so this only needs to happen once here.
+        prime_bit_count(&mut sm, NBITS);
+
+        let miso: Pin<Miso::Id, P::PinFunction, Miso::Pull> = miso.into_function();
+        let mosi: Pin<Mosi::Id, P::PinFunction, Mosi::Pull> = mosi.into_function();
+        let clk: Pin<Clk::Id, P::PinFunction, Clk::Pull> = clk.into_function();
+
+        let sm = sm.start();
+
+        Ok(Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            miso,
+            mosi,
+            clk,
+        })
+    }
+
+    /// Frees the state machine and pins, returning an uninitialised state
+    /// machine that can be reused for another program.
+    pub fn free(
+        self,
+    ) -> (
+        (Miso::Type, Mosi::Type, Clk::Type),
+        UninitStateMachine<(P, SMI)>,
+    )
+    where
+        Miso::Id: ValidFunction<Miso::Function>,
+        Mosi::Id: ValidFunction<Mosi::Function>,
+        Clk::Id: ValidFunction<Clk::Function>,
+    {
+        let Self {
+            pio,
+            sm,
+            tx,
+            rx,
+            miso,
+            mosi,
+            clk,
+        } = self;
+        let (uninit, program) = sm.uninit(rx, tx);
+        pio.uninstall(program);
+
+        ((miso.reconfigure(), mosi.reconfigure(), clk.reconfigure()), uninit)
+    }
+
+    fn transfer_word(&mut self, word: u32) -> u32 {
+        self.transfer_bits(word, NBITS)
+    }
+
+    /// Clocks out the top `nbits` bits of `data` while simultaneously
+    /// clocking in `nbits` bits, returning the captured value right-aligned
+    /// in the low bits of the result.
+    ///
+    /// Re-primes `X`/`Y` for `nbits` first (see [`prime_bit_count`]), so
+    /// this is safe to call with a different `nbits` than the previous
+    /// call, including between calls to the byte-oriented `embedded-hal`
+    /// methods (which always re-prime for `NBITS`).
+    fn transfer_bits(&mut self, data: u32, nbits: u8) -> u32 {
+        prime_bit_count(&mut self.sm, nbits);
+
+        while self.tx.is_full() {}
+        self.tx.write(data << (32 - u32::from(nbits)));
+
+        loop {
+            if let Some(word) = self.rx.read() {
+                return word >> (32 - u32::from(nbits));
+            }
+        }
+    }
+
+    /// Clocks out `nbits` bits of `data` (MSB-first), discarding the bits
+    /// simultaneously clocked in. `nbits` must be between 2 and 32.
+    ///
+    /// Unlike the byte-oriented `embedded-hal` methods, this (and
+    /// [`Self::read_bits`]) can be called with a different bit width on
+    /// every invocation, letting a single configured [`Spi`] issue
+    /// transfers of arbitrary, runtime-chosen widths.
+    pub fn write_bits(&mut self, data: u32, nbits: u8) {
+        self.transfer_bits(data, nbits);
+    }
+
+    /// Clocks in `nbits` bits while driving zeros out, returning the
+    /// captured value right-aligned in the low bits of the result. `nbits`
+    /// must be between 2 and 32.
+    pub fn read_bits(&mut self, nbits: u8) -> u32 {
+        self.transfer_bits(0, nbits)
+    }
+
+    /// Starts a DMA-paced transfer, claiming `tx_channel` to stream `tx_buf`
+    /// into the TX FIFO and `rx_channel` to drain the RX FIFO into `rx_buf`,
+    /// both paced by the state machine's DREQs so the CPU is free until the
+    /// returned [`Transfer`]'s `wait()` is called.
+    ///
+    /// See [`Transfer`] for why the buffers are `u32` slices rather than
+    /// `u8` ones.
+    pub fn transfer_dma<TxCh, RxCh, TxBuf, RxBuf>(
+        self,
+        tx_channel: TxCh,
+        rx_channel: RxCh,
+        tx_buf: TxBuf,
+        rx_buf: RxBuf,
+    ) -> Transfer<'pio, P, SMI, Miso, Mosi, Clk, TxCh, RxCh, TxBuf, RxBuf, NBITS>
+    where
+        TxCh: SingleChannel,
+        RxCh: SingleChannel,
+        TxBuf: ReadTarget<ReceivedWord = u32>,
+        RxBuf: WriteTarget<TransmittedWord = u32>,
+    {
+        Transfer::start(self, tx_channel, rx_channel, tx_buf, rx_buf)
+    }
+
+    /// Wraps this `Spi` in an [`AsyncSpi`], implementing `embedded-hal-async`'s
+    /// `SpiBus` by waiting on the state machine's FIFO interrupts instead of
+    /// busy-polling.
+    ///
+    /// `irq` is the PIO IRQ line (`IRQ0` or `IRQ1`) whose interrupt the
+    /// application will forward to [`on_pio0_irq`]/[`on_pio1_irq`]; it isn't
+    /// otherwise configured here, so the application is still responsible
+    /// for unmasking and routing that NVIC interrupt.
+    pub fn into_async(self, irq: PioIRQ) -> AsyncSpi<'pio, P, SMI, Miso, Mosi, Clk, NBITS> {
+        AsyncSpi::new(self, irq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `write_bits`/`read_bits` support a runtime-chosen `nbits` different
+    /// from `NBITS`, which only works if the assembled program's per-word
+    /// FIFO refill is gated by the trip count rather than by the hardware's
+    /// (fixed-at-build-time) autopull/autopush threshold. Assert the
+    /// assembled program does exactly one unconditional `pull`/`push` per
+    /// word, for both phases.
+    #[test]
+    fn bitloop_pull_and_push_are_unconditional() {
+        for phase in [Phase::CaptureOnFirstTransition, Phase::CaptureOnSecondTransition] {
+            let program = build_program(phase);
+            let side_set = program.side_set;
+
+            let mut pulls = 0;
+            let mut pushes = 0;
+            for &raw in program.code.iter() {
+                match pio::Instruction::decode(raw, side_set).unwrap().operands {
+                    pio::InstructionOperands::PULL { if_empty, block } => {
+                        assert!(block && !if_empty, "pull must not be threshold-gated");
+                        pulls += 1;
+                    }
+                    pio::InstructionOperands::PUSH { if_full, block } => {
+                        assert!(block && !if_full, "push must not be threshold-gated");
+                        pushes += 1;
+                    }
+                    _ => {}
+                }
+            }
+
+            assert_eq!(pulls, 1, "expected exactly one pull per word");
+            assert_eq!(pushes, 1, "expected exactly one push per word");
+        }
+    }
+
+    /// A missing (or too-late) `pull` doesn't change the pull/push *count*
+    /// [`bitloop_pull_and_push_are_unconditional`] checks, just its
+    /// position, so that test alone can't catch an `out` reached before the
+    /// OSR is ever loaded (which clocks zeros instead of the caller's data
+    /// for the whole first word, and leaves the bus permanently a word
+    /// behind from then on). Assert the first `pull` precedes the first
+    /// `out` for both phases.
+    #[test]
+    fn bitloop_pulls_before_shifting_out() {
+        for phase in [Phase::CaptureOnFirstTransition, Phase::CaptureOnSecondTransition] {
+            let program = build_program(phase);
+            let side_set = program.side_set;
+
+            let mut first_pull = None;
+            let mut first_out = None;
+            for (index, &raw) in program.code.iter().enumerate() {
+                match pio::Instruction::decode(raw, side_set).unwrap().operands {
+                    pio::InstructionOperands::PULL { .. } if first_pull.is_none() => {
+                        first_pull = Some(index);
+                    }
+                    pio::InstructionOperands::OUT { .. } if first_out.is_none() => {
+                        first_out = Some(index);
+                    }
+                    _ => {}
+                }
+            }
+
+            assert!(
+                first_pull.unwrap() < first_out.unwrap(),
+                "the OSR must be loaded before the first bit is shifted out"
+            );
+        }
+    }
+}